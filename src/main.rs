@@ -1,14 +1,18 @@
 use anyhow::Context;
 use axum::{
     body::Bytes,
-    extract::{DefaultBodyLimit, Query, State},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, Query, State,
+    },
     http::StatusCode,
     response::{sse, Html, IntoResponse, Response},
     routing::{get, post},
     Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
 use directories::ProjectDirs;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::path::PathBuf;
@@ -34,10 +38,151 @@ struct AppState {
 }
 
 struct ServerProcess {
-    tcp_stream: tokio::net::TcpStream,
+    tcp_stream: tokio::net::tcp::OwnedWriteHalf,
     server_path: PathBuf,
     map_path: PathBuf,
     port: u16,
+    pid: u32,
+    player_count: usize,
+    last_activity: tokio::time::Instant,
+    // Set once `sv_shutdown_when_empty` has been sent, so the sweep doesn't resend it every tick.
+    shutdown_scheduled: bool,
+}
+
+// On-disk mirror of `AppState::processes`, so a restarted trashmap can find servers that
+// were left running by a previous instance instead of leaking their ports and directories.
+#[derive(Serialize, Deserialize, Default)]
+struct Registry {
+    server: Vec<RegistryEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RegistryEntry {
+    server_id: Uuid,
+    port: u16,
+    server_path: PathBuf,
+    map_path: PathBuf,
+    pid: u32,
+}
+
+fn registry_path(project_dirs: &ProjectDirs) -> PathBuf {
+    project_dirs.data_dir().join("processes.toml")
+}
+
+async fn save_registry(
+    project_dirs: &ProjectDirs,
+    processes: &HashMap<Uuid, ServerProcess>,
+) -> Result<(), anyhow::Error> {
+    let registry = Registry {
+        server: processes
+            .iter()
+            .map(|(&server_id, process)| RegistryEntry {
+                server_id,
+                port: process.port,
+                server_path: process.server_path.clone(),
+                map_path: process.map_path.clone(),
+                pid: process.pid,
+            })
+            .collect(),
+    };
+
+    tokio::fs::create_dir_all(project_dirs.data_dir()).await?;
+    tokio::fs::write(registry_path(project_dirs), toml::to_string(&registry)?).await?;
+    Ok(())
+}
+
+// Reconnects to servers left running by a previous instance of trashmap (found via the
+// on-disk registry) and cleans up the directories of any that didn't survive the restart.
+async fn adopt_processes(state: &AppState) -> Result<(), anyhow::Error> {
+    let registry = match tokio::fs::read_to_string(registry_path(&state.project_dirs)).await {
+        Ok(contents) => toml::from_str::<Registry>(&contents)?,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error.into()),
+    };
+
+    let mut processes = state.processes.lock().await;
+    for entry in registry.server {
+        let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(1);
+        let handshake = async {
+            let mut tcp_stream =
+                tokio::time::timeout_at(deadline, tokio::net::TcpStream::connect(("127.0.0.1", entry.port)))
+                    .await??;
+            tcp_stream.write_all(b"open sesame\n").await?;
+            // The child's own stdout is never drained past the startup banner loop, so
+            // silence it; join/chat/console lines come from the econ mirror instead
+            // (ec_output_level, set once in autoexec.cfg and unaffected by a reconnect).
+            tcp_stream.write_all(b"stdout_output_level -3\n").await?;
+            Ok::<_, anyhow::Error>(tcp_stream)
+        };
+
+        // A dead entry (connect refused or not accepted within a second, or a failed write)
+        // is dropped from the registry and its directory reclaimed, without aborting recovery
+        // for other entries. A process that accepts the connection is adopted without checking
+        // that it actually answers as econ.
+        let tcp_stream = match handshake.await {
+            Ok(tcp_stream) => tcp_stream,
+            Err(_) => {
+                if let Err(error) = tokio::fs::remove_dir_all(&entry.server_path).await {
+                    if error.kind() != std::io::ErrorKind::NotFound {
+                        return Err(error.into());
+                    }
+                }
+                continue;
+            }
+        };
+
+        let (econ_read, mut econ_write) = tcp_stream.into_split();
+        let mut lines = tokio::io::BufReader::new(econ_read).lines();
+
+        // Seed the real player count from econ's `status` reply instead of assuming nobody's
+        // connected, so a restart during a genuinely active session doesn't trip the idle
+        // sweep into reporting it as empty.
+        let player_count = if econ_write.write_all(b"status\n").await.is_ok() {
+            count_status_players(&mut lines).await
+        } else {
+            0
+        };
+
+        tokio::task::spawn(read_econ(state.clone(), entry.server_id, lines));
+
+        println!(
+            "Re-adopted server {} on port {} with {player_count} player(s)",
+            entry.server_id, entry.port
+        );
+
+        processes.insert(
+            entry.server_id,
+            ServerProcess {
+                tcp_stream: econ_write,
+                server_path: entry.server_path,
+                map_path: entry.map_path,
+                port: entry.port,
+                pid: entry.pid,
+                player_count,
+                last_activity: tokio::time::Instant::now(),
+                shutdown_scheduled: false,
+            },
+        );
+    }
+
+    save_registry(&state.project_dirs, &processes).await
+}
+
+// Reads econ's reply to a `status` command (one line per connected client) until a short
+// quiet period passes, and counts how many players are actually connected.
+async fn count_status_players(
+    lines: &mut tokio::io::Lines<tokio::io::BufReader<tokio::net::tcp::OwnedReadHalf>>,
+) -> usize {
+    let mut player_count = 0;
+    loop {
+        let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_millis(500);
+        match tokio::time::timeout_at(deadline, lines.next_line()).await {
+            Ok(Ok(Some(line))) if line.to_lowercase().contains("id=") => player_count += 1,
+            Ok(Ok(Some(_))) => {}
+            Ok(Ok(None)) | Ok(Err(_)) | Err(_) => break,
+        }
+    }
+    player_count
 }
 
 #[derive(Clone)]
@@ -53,6 +198,9 @@ struct Config {
     executable_path: PathBuf,
     port_range: (u16, u16),
     public_address: String,
+    tls_cert_path: Option<PathBuf>,
+    tls_key_path: Option<PathBuf>,
+    idle_timeout_secs: u64,
 }
 
 #[tokio::main]
@@ -73,9 +221,12 @@ async fn main() -> Result<(), anyhow::Error> {
         processes: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
     };
 
+    adopt_processes(&state).await?;
+
     let app = Router::new()
         .route("/server-events", get(server_events))
         .route("/update-settings", get(update_settings))
+        .route("/rcon", get(rcon))
         .route(
             "/update-map",
             post(update_map).layer(
@@ -97,6 +248,9 @@ async fn main() -> Result<(), anyhow::Error> {
             )
     };
 
+    let idle_state = state.clone();
+    tokio::spawn(log_errors(async move { sweep_idle_servers(idle_state).await }));
+
     tokio::spawn(log_errors(async move {
         let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
         tokio::select! {
@@ -110,13 +264,33 @@ async fn main() -> Result<(), anyhow::Error> {
 
             tokio::fs::remove_dir_all(&process.server_path).await?;
         }
+        processes.clear();
+        save_registry(&state.project_dirs, &processes).await?;
 
         std::process::exit(0);
     }));
 
-    let listener = tokio::net::TcpListener::bind(("127.0.0.1", config.http_port)).await?;
-    println!("Listening on http://{}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
+    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], config.http_port));
+
+    match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let tls_config = RustlsConfig::from_pem_file(cert_path, key_path)
+                .await
+                .context("Failed to load the TLS certificate or key")?;
+
+            println!("Listening on https://{addr}");
+            axum_server::bind_rustls(addr, tls_config)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        (None, None) => {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            println!("Listening on http://{}", listener.local_addr()?);
+            axum::serve(listener, app).await?;
+        }
+        _ => anyhow::bail!("tls_cert_path and tls_key_path must be configured together"),
+    }
+
     Ok(())
 }
 
@@ -158,6 +332,125 @@ fn escape_ddnet(str: &str) -> String {
     str.replace(r"\", r"\\").replace("\"", "\\\"").replace("\n", "").replace("\r", "")
 }
 
+// Commands the web console is allowed to tunnel over econ. Mirrors the access levels
+// already granted in autoexec.cfg, so the interactive console can't do anything the
+// tester couldn't already do through the in-game rcon.
+const ALLOWED_RCON_COMMANDS: &[&str] = &[
+    "practice",
+    "totele",
+    "totelecp",
+    "tele",
+    "addweapon",
+    "removeweapon",
+    "shotgun",
+    "unshotgun",
+    "grenade",
+    "ungrenade",
+    "laser",
+    "unlaser",
+    "rifle",
+    "unrifle",
+    "jetpack",
+    "unjetpack",
+    "weapons",
+    "unweapons",
+    "ninja",
+    "unninja",
+    "invincible",
+    "endless_hook",
+    "unendless_hook",
+    "solo",
+    "unsolo",
+    "freeze",
+    "unfreeze",
+    "deep",
+    "undeep",
+    "livefreeze",
+    "unlivefreeze",
+    "setjumps",
+    "left",
+    "right",
+    "up",
+    "down",
+    "move",
+    "move_raw",
+];
+
+#[derive(Deserialize)]
+struct RconQuery {
+    server_id: Uuid,
+}
+
+async fn rcon(
+    State(state): State<AppState>,
+    Query(query): Query<RconQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    // Subscribe before checking liveness (as `server_events` does), so a `stopped` event
+    // published between the check and the upgrade completing isn't missed.
+    let events = state.event_channel.subscribe();
+
+    let processes = state.processes.lock().await;
+    if !processes.contains_key(&query.server_id) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    drop(processes);
+
+    ws.on_upgrade(move |socket| handle_rcon(state, query.server_id, socket, events))
+}
+
+async fn handle_rcon(
+    state: AppState,
+    server_id: Uuid,
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<ServerEvent>,
+) {
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                match event {
+                    Ok(event) if event.server_id == server_id => {
+                        if event.event == "stopped" {
+                            break;
+                        }
+                        // Only forward econ-sourced console output; `online`/`offline`/
+                        // `shutdownwhenempty` aren't part of the interactive console stream.
+                        if !matches!(event.event.as_str(), "console" | "chat" | "join" | "leave") {
+                            continue;
+                        }
+                        if socket.send(Message::Text(event.data)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            message = socket.recv() => {
+                let Some(Ok(message)) = message else { break };
+                let Message::Text(command) = message else { continue };
+                let Some(command_name) = command.split_whitespace().next() else { continue };
+                // Reject `;` so no further command can be chained after an allowed one.
+                if !ALLOWED_RCON_COMMANDS.contains(&command_name) || command.contains(';') {
+                    continue;
+                }
+
+                let mut processes = state.processes.lock().await;
+                let Some(process) = processes.get_mut(&server_id) else { break };
+                if process
+                    .tcp_stream
+                    .write_all(format!("{}\n", escape_ddnet(&command)).as_bytes())
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct UpdateSettingsQuery {
     server_id: Uuid,
@@ -228,6 +521,7 @@ async fn update_map(
                 .await?;
             tokio::fs::remove_file(&process.map_path).await?;
             process.map_path = map_path;
+            save_registry(&state.project_dirs, &processes).await?;
         }
 
         Ok(StatusCode::ACCEPTED)
@@ -252,7 +546,7 @@ async fn update_map(
                 &format!("ec_port {port}\n"),
                 "ec_bindaddr \"127.0.0.1\"\n",
                 "ec_password \"open sesame\"\n",
-                "ec_output_level -3\n", // Prevent the TCP buffer running full
+                "ec_output_level 1\n", // Mirror join/chat/console lines to econ clients
                 "sv_motd \"Use rcon password \\\"test\\\" or /practice for testing. Instead of \\\"super\\\" use \\\"invincible\\\" to toggle invincibility.\"\n",
                 "sv_test_cmds 1\n",
                 "sv_rescue 1\n",
@@ -305,23 +599,13 @@ async fn update_map(
             .stdout(std::process::Stdio::piped())
             .spawn()?;
         let stdout = child.stdout.take().unwrap();
+        let pid = child.id().context("Could not read the child process's PID")?;
 
-        let state_clone = state.clone();
-        let server_path_clone = server_path.clone();
+        // The econ reader task (spawned below, once the handshake completes) is what notices
+        // the process going away and cleans up `processes`/`server_path`; this just reaps it
+        // so it doesn't linger as a zombie.
         tokio::task::spawn(log_errors(async move {
             child.wait().await?;
-
-            let mut processes = state_clone.processes.lock().await;
-            if processes.remove(&query.server_id).is_some() {
-                let _ = state_clone.event_channel.send(ServerEvent {
-                    server_id: query.server_id,
-                    event: "stopped".to_owned(),
-                    data: String::new(),
-                });
-            }
-
-            tokio::fs::remove_dir_all(server_path_clone).await?;
-
             Ok(())
         }));
 
@@ -339,17 +623,33 @@ async fn update_map(
         let mut tcp_stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
 
         tcp_stream.write_all(b"open sesame\n").await?;
-        tcp_stream.write_all(b"stdout_output_level -3\n").await?; // Prevent the pipe running full
+        // The child's own stdout (Stdio::piped() above) is never drained past the banner
+        // loop, so silence it now the econ connection (mirrored via ec_output_level in
+        // autoexec.cfg) is up, or the pipe fills and the server blocks on its next write.
+        tcp_stream.write_all(b"stdout_output_level -3\n").await?;
+
+        let (econ_read, econ_write) = tcp_stream.into_split();
+
+        tokio::task::spawn(read_econ(
+            state.clone(),
+            query.server_id,
+            tokio::io::BufReader::new(econ_read).lines(),
+        ));
 
         processes.insert(
             query.server_id,
             ServerProcess {
-                tcp_stream,
+                tcp_stream: econ_write,
                 server_path,
                 map_path,
                 port,
+                pid,
+                player_count: 0,
+                last_activity: tokio::time::Instant::now(),
+                shutdown_scheduled: false,
             },
         );
+        save_registry(&state.project_dirs, &processes).await?;
 
         let _ = state.event_channel.send(ServerEvent {
             server_id: query.server_id,
@@ -357,28 +657,107 @@ async fn update_map(
             data: format!("{}:{}", state.config.public_address, port),
         });
 
-        let state_clone = state.clone();
-        tokio::task::spawn(log_errors(async move {
-            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+        Ok(StatusCode::CREATED)
+    }
+}
+
+// Tails the econ connection for the lifetime of the server process and republishes
+// interesting lines through `event_channel` so every subscribed SSE client sees them live.
+// Takes an already-constructed `Lines` (rather than the raw half) so callers can consume
+// a few lines up front, e.g. to read the reply to a `status` query, without losing
+// whatever the `BufReader` already buffered past them.
+async fn read_econ(
+    state: AppState,
+    server_id: Uuid,
+    mut lines: tokio::io::Lines<tokio::io::BufReader<tokio::net::tcp::OwnedReadHalf>>,
+) {
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) | Err(_) => break,
+        };
+
+        let event = if line.contains("entered the game") {
+            "join"
+        } else if line.contains("client dropped") {
+            "leave"
+        } else if line.contains("Chat:") {
+            "chat"
+        } else {
+            "console"
+        };
+
+        {
+            let mut processes = state.processes.lock().await;
+            if let Some(process) = processes.get_mut(&server_id) {
+                match event {
+                    "join" => process.player_count += 1,
+                    "leave" => process.player_count = process.player_count.saturating_sub(1),
+                    _ => {}
+                }
+                process.last_activity = tokio::time::Instant::now();
+
+                if event == "join" && process.shutdown_scheduled {
+                    process.shutdown_scheduled = false;
+                    let _ = process.tcp_stream.write_all(b"sv_shutdown_when_empty 0\n").await;
+                }
+            }
+        }
 
-            let mut processes = state_clone.processes.lock().await;
-            if let Some(process) = processes.get_mut(&query.server_id) {
-                process
+        let _ = state.event_channel.send(ServerEvent {
+            server_id,
+            event: event.to_owned(),
+            data: line,
+        });
+    }
+
+    let mut processes = state.processes.lock().await;
+    if let Some(process) = processes.remove(&server_id) {
+        let _ = tokio::fs::remove_dir_all(&process.server_path).await;
+        let _ = save_registry(&state.project_dirs, &processes).await;
+
+        let _ = state.event_channel.send(ServerEvent {
+            server_id,
+            event: "stopped".to_owned(),
+            data: String::new(),
+        });
+    }
+}
+
+// Periodically checks every running server and schedules a shutdown once it has been
+// empty for `idle_timeout_secs`, so a freshly uploaded map stays up as long as someone
+// is actually testing it instead of dying on a fixed timer.
+async fn sweep_idle_servers(state: AppState) -> Result<(), anyhow::Error> {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+
+        let idle_timeout = tokio::time::Duration::from_secs(state.config.idle_timeout_secs);
+        let mut processes = state.processes.lock().await;
+        for (&server_id, process) in processes.iter_mut() {
+            if process.player_count == 0
+                && !process.shutdown_scheduled
+                && process.last_activity.elapsed() >= idle_timeout
+            {
+                // A write failure here means this one server's econ connection is wedged,
+                // not that the sweep itself should stop running for everyone else.
+                if process
                     .tcp_stream
                     .write_all(b"sv_shutdown_when_empty 1\n")
-                    .await?;
-
-                let _ = state_clone.event_channel.send(ServerEvent {
-                    server_id: query.server_id,
+                    .await
+                    .is_err()
+                {
+                    continue;
+                }
+                process.shutdown_scheduled = true;
+
+                let _ = state.event_channel.send(ServerEvent {
+                    server_id,
                     event: "shutdownwhenempty".to_owned(),
                     data: String::new(),
                 });
             }
-
-            Ok(())
-        }));
-
-        Ok(StatusCode::CREATED)
+        }
     }
 }
 